@@ -0,0 +1,78 @@
+use clap::{Parser, ValueEnum};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BaudRate {
+    #[value(name = "2400")]
+    B2400,
+    #[value(name = "4800")]
+    B4800,
+    #[value(name = "9600")]
+    B9600,
+    #[value(name = "19200")]
+    B19200,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StopBits {
+    #[value(name = "1")]
+    One,
+    #[value(name = "2")]
+    Two,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DisplayingMode {
+    Binaire,
+    Decimal,
+    Hexadecimal,
+    Ascii,
+    Hexdump,
+    Text,
+}
+
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[arg(long, value_enum, default_value = "2400")]
+    pub baud: BaudRate,
+
+    #[arg(long, value_enum, default_value = "none")]
+    pub parity: Parity,
+
+    // Rides along in the otherwise-unused high byte of wIndex / 4th cmd byte.
+    #[arg(long, value_enum, default_value = "1")]
+    pub stop_bits: StopBits,
+
+    #[arg(long, value_enum, default_value = "hexadecimal")]
+    pub mode: DisplayingMode,
+
+    #[arg(long)]
+    pub saut: Option<u32>,
+
+    // Dumps the direction, byte count, and bytes of every serial read and
+    // write to stderr; useful to tell a silent link from a framing bug. The
+    // initial serial-settings transfer is not dumped.
+    #[arg(long)]
+    pub trace: bool,
+
+    // Selects among several matching devices by position in the enumeration
+    // order; takes precedence over --device-serial when both are given.
+    #[arg(long)]
+    pub device_index: Option<usize>,
+
+    // Selects among several matching devices by their USB serial number.
+    #[arg(long)]
+    pub device_serial: Option<String>,
+
+    // Bypasses the packet-display loop and copies the device's raw byte
+    // stream straight to stdout through UsbSerialStream's std::io::Read
+    // impl, instead of the framed `--mode` display.
+    #[arg(long)]
+    pub raw: bool,
+}
@@ -0,0 +1,53 @@
+mod args;
+mod usb;
+
+use anyhow::{Context, Result};
+use args::Args;
+use clap::Parser;
+use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc};
+use usb::SerialUsb;
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let selector = match (&args.device_index, &args.device_serial) {
+        (Some(index), _) => Some(usb::DeviceSelector::Index(*index)),
+        (None, Some(serial)) => Some(usb::DeviceSelector::Serial(serial.clone())),
+        (None, None) => None,
+    };
+    let device = usb::select_device(selector)?;
+    let handle = device.open().context("Failed to open USB device")?;
+    let handle = usb::TracingSerialUsb::new(handle, args.trace);
+
+    handle.init_serial_usb(args.baud, args.parity, args.stop_bits)?;
+
+    if args.raw {
+        let mut stream = usb::UsbSerialStream::new(handle);
+        std::io::copy(&mut stream, &mut std::io::stdout())
+            .context("Failed to copy from the serial stream")?;
+        return Ok(());
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = stop.clone();
+        ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))
+            .context("Failed to install Ctrl-C handler")?;
+    }
+    let (reader, receiver) = usb::spawn_reader(handle, stop.clone());
+
+    let mut state = usb::PrintState::default();
+    let result = (|| -> Result<()> {
+        for packet in receiver {
+            let buffer = packet?;
+            args.mode.print(&buffer, args.saut, &mut state);
+        }
+        Ok(())
+    })();
+    args.mode.finish(&mut state);
+
+    stop.store(true, Ordering::Relaxed);
+    let _ = reader.join();
+
+    result
+}
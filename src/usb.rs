@@ -1,7 +1,14 @@
-use crate::args::DisplayingMode;
-use anyhow::{Context, Result};
+use crate::args::{BaudRate, DisplayingMode, Parity, StopBits};
+use anyhow::{anyhow, Context, Result};
 use rusb::{Device, DeviceHandle, GlobalContext};
-use std::{io::Write, time::Duration};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc,
+};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 // Identifiant de la carte de INF1900
 const VENDOR_ID: u16 = 0x16c0;
@@ -15,7 +22,16 @@ const USBASP_FUNC_SETSERIOS: u8 = 11;
 const USBASP_FUNC_READSER: u8 = 12;
 const USBASP_FUNC_WRITESER: u8 = 13;
 const USBASP_MODE_PARITYN: u8 = 1;
+const USBASP_MODE_PARITYO: u8 = 2;
+const USBASP_MODE_PARITYE: u8 = 3;
+
+const USBASP_MODE_STOPBIT1: u8 = 0;
+const USBASP_MODE_STOPBIT2: u8 = 1;
+
 const USBASP_MODE_SETBAUD2400: u8 = 0x13;
+const USBASP_MODE_SETBAUD4800: u8 = 0x23;
+const USBASP_MODE_SETBAUD9600: u8 = 0x33;
+const USBASP_MODE_SETBAUD19200: u8 = 0x43;
 
 pub const PACKET_SIZE: u8 = 8;
 
@@ -25,11 +41,87 @@ fn is_device_corresponding(device: Device<GlobalContext>) -> Option<Device<Globa
         .then_some(device)
 }
 
-pub fn find_device() -> Option<Device<GlobalContext>> {
+pub fn find_devices() -> Vec<Device<GlobalContext>> {
     rusb::devices()
-        .ok()?
+        .map(|list| list.iter().filter_map(is_device_corresponding).collect())
+        .unwrap_or_default()
+}
+
+pub enum DeviceSelector {
+    Index(usize),
+    Serial(String),
+}
+
+fn device_serial(device: &Device<GlobalContext>) -> Option<String> {
+    let handle = device.open().ok()?;
+    let descriptor = device.device_descriptor().ok()?;
+    let language = *handle.read_languages(Duration::from_secs(1)).ok()?.first()?;
+    handle
+        .read_serial_number_string(language, &descriptor, Duration::from_secs(1))
+        .ok()
+}
+
+fn describe_candidates(devices: &[Device<GlobalContext>]) -> String {
+    devices
         .iter()
-        .find_map(is_device_corresponding)
+        .map(|device| {
+            format!(
+                "bus {:03} address {:03} serial {}",
+                device.bus_number(),
+                device.address(),
+                device_serial(device).as_deref().unwrap_or("<unknown>")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Picks the INF1900 board to talk to when several are plugged in. With no
+// selector, a single candidate is used as before; more than one requires an
+// explicit --device-index or --device-serial.
+pub fn select_device(selector: Option<DeviceSelector>) -> Result<Device<GlobalContext>> {
+    let mut devices = find_devices();
+    match selector {
+        Some(DeviceSelector::Index(index)) => (index < devices.len())
+            .then(|| devices.remove(index))
+            .ok_or_else(|| anyhow!("Device index {index} out of range (found {} device(s))\n{}", devices.len(), describe_candidates(&devices))),
+        Some(DeviceSelector::Serial(serial)) => devices
+            .into_iter()
+            .find(|device| device_serial(device).as_deref() == Some(serial.as_str()))
+            .ok_or_else(|| anyhow!("No device with serial number {serial}")),
+        None => match devices.len() {
+            0 => Err(anyhow!("No matching USB device found")),
+            1 => Ok(devices.remove(0)),
+            _ => Err(anyhow!(
+                "Multiple matching devices found, pick one with --device-index or --device-serial:\n{}",
+                describe_candidates(&devices)
+            )),
+        },
+    }
+}
+
+fn baud_rate_mode(baud: BaudRate) -> u8 {
+    match baud {
+        BaudRate::B2400 => USBASP_MODE_SETBAUD2400,
+        BaudRate::B4800 => USBASP_MODE_SETBAUD4800,
+        BaudRate::B9600 => USBASP_MODE_SETBAUD9600,
+        BaudRate::B19200 => USBASP_MODE_SETBAUD19200,
+    }
+}
+
+fn parity_mode(parity: Parity) -> u8 {
+    match parity {
+        Parity::None => USBASP_MODE_PARITYN,
+        Parity::Even => USBASP_MODE_PARITYE,
+        Parity::Odd => USBASP_MODE_PARITYO,
+    }
+}
+
+fn stop_bits_mode(stop_bits: StopBits) -> u8 {
+    match stop_bits {
+        StopBits::One => USBASP_MODE_STOPBIT1,
+        StopBits::Two => USBASP_MODE_STOPBIT2,
+    }
 }
 
 fn bits_from_buffer(bytes: &[u8; PACKET_SIZE as usize]) -> &[u8] {
@@ -47,32 +139,105 @@ fn print_saut(pos: &mut u32, saut: Option<u32>) {
     }
 }
 
+const HEXDUMP_ROW_WIDTH: usize = 16;
+
+fn hexdump_ascii_gutter(row: &[u8]) -> String {
+    row.iter()
+        .map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+fn format_hexdump_row(offset: u64, row: &[u8]) -> String {
+    let mut line = format!("{offset:08x}  ");
+    for i in 0..HEXDUMP_ROW_WIDTH {
+        match row.get(i) {
+            Some(byte) => line.push_str(&format!("{byte:02x} ")),
+            None => line.push_str("   "),
+        }
+        if i == HEXDUMP_ROW_WIDTH / 2 - 1 {
+            line.push(' ');
+        }
+    }
+    line.push_str(&format!(" |{}|", hexdump_ascii_gutter(row)));
+    line
+}
+
+fn print_hexdump_row(offset: u64, row: &[u8]) {
+    println!("{}", format_hexdump_row(offset, row));
+}
+
+fn decode_text_line(line: Vec<u8>) -> std::result::Result<String, std::string::FromUtf8Error> {
+    String::from_utf8(line)
+}
+
+fn print_text_line(line: Vec<u8>) {
+    match decode_text_line(line) {
+        Ok(text) => print!("{text}"),
+        Err(err) => eprintln!("warning: malformed UTF-8 in line: {err}"),
+    }
+}
+
+// Carries the state that must survive across packet boundaries: the `saut`
+// column counter shared by the simple modes, the hexdump's running offset
+// plus partially-filled 16-byte row, and the text mode's line-in-progress.
+#[derive(Default)]
+pub struct PrintState {
+    pos: u32,
+    hexdump_offset: u64,
+    hexdump_row: Vec<u8>,
+    text_line: Vec<u8>,
+}
+
 impl DisplayingMode {
-    pub fn print(self, buffer: &[u8; PACKET_SIZE as usize], saut: Option<u32>, pos: &mut u32) {
+    pub fn print(self, buffer: &[u8; PACKET_SIZE as usize], saut: Option<u32>, state: &mut PrintState) {
         let bytes = bits_from_buffer(buffer);
         match self {
             DisplayingMode::Binaire => {
                 for byte in bytes {
                     print!("{byte:b}");
-                    print_saut(pos, saut);
+                    print_saut(&mut state.pos, saut);
                 }
             }
             DisplayingMode::Decimal => {
                 for byte in bytes {
                     print!("{byte}");
-                    print_saut(pos, saut);
+                    print_saut(&mut state.pos, saut);
                 }
             }
             DisplayingMode::Hexadecimal => {
                 for byte in bytes {
                     print!("{byte:X}");
-                    print_saut(pos, saut);
+                    print_saut(&mut state.pos, saut);
                 }
             }
             DisplayingMode::Ascii => {
                 for byte in bytes {
                     print!("{}", *byte as char);
-                    print_saut(pos, saut);
+                    print_saut(&mut state.pos, saut);
+                }
+            }
+            DisplayingMode::Hexdump => {
+                for &byte in bytes {
+                    state.hexdump_row.push(byte);
+                    if state.hexdump_row.len() == HEXDUMP_ROW_WIDTH {
+                        print_hexdump_row(state.hexdump_offset, &state.hexdump_row);
+                        state.hexdump_offset += HEXDUMP_ROW_WIDTH as u64;
+                        state.hexdump_row.clear();
+                    }
+                }
+            }
+            DisplayingMode::Text => {
+                for &byte in bytes {
+                    state.text_line.push(byte);
+                    if byte == b'\n' {
+                        print_text_line(std::mem::take(&mut state.text_line));
+                    }
                 }
             }
         }
@@ -81,29 +246,40 @@ impl DisplayingMode {
             return;
         };
     }
+
+    // Flushes a final short hexdump row or an unterminated text line at
+    // end-of-stream; a no-op for every other mode.
+    pub fn finish(self, state: &mut PrintState) {
+        if matches!(self, DisplayingMode::Hexdump) && !state.hexdump_row.is_empty() {
+            print_hexdump_row(state.hexdump_offset, &state.hexdump_row);
+            state.hexdump_offset += state.hexdump_row.len() as u64;
+            state.hexdump_row.clear();
+        }
+        if matches!(self, DisplayingMode::Text) && !state.text_line.is_empty() {
+            print_text_line(std::mem::take(&mut state.text_line));
+        }
+    }
 }
 
 pub trait SerialUsb {
-    fn init_serial_usb(&self) -> Result<()>;
+    fn init_serial_usb(&self, baud: BaudRate, parity: Parity, stop_bits: StopBits) -> Result<()>;
     fn read_serial_usb(&self, buffer: &mut [u8; 8]) -> Result<()>;
     fn write_serial_usb(&self, buffer: &[u8]) -> Result<()>;
 }
 
 impl SerialUsb for DeviceHandle<GlobalContext> {
-    fn init_serial_usb(&self) -> Result<()> {
+    fn init_serial_usb(&self, baud: BaudRate, parity: Parity, stop_bits: StopBits) -> Result<()> {
         let mut buffer = [0; 4];
-        let cmd = [
-            USBASP_MODE_SETBAUD2400,
-            PACKET_SIZE as u8,
-            USBASP_MODE_PARITYN as u8,
-            0,
-        ];
+        let baud_mode = baud_rate_mode(baud);
+        let parity_mode = parity_mode(parity);
+        let stop_bits_mode = stop_bits_mode(stop_bits);
+        let cmd = [baud_mode, PACKET_SIZE, parity_mode, stop_bits_mode];
         // Error with negative integer are handled by rusb
         let nb_bytes: usize = self.read_control(
             REQUEST_READ,
             USBASP_FUNC_SETSERIOS,
-            ((PACKET_SIZE as u16) << 8) | USBASP_MODE_SETBAUD2400 as u16,
-            USBASP_MODE_PARITYN as u16,
+            ((PACKET_SIZE as u16) << 8) | baud_mode as u16,
+            ((stop_bits_mode as u16) << 8) | parity_mode as u16,
             &mut buffer,
             Duration::from_secs(2),
         )?;
@@ -140,3 +316,208 @@ impl SerialUsb for DeviceHandle<GlobalContext> {
         Ok(())
     }
 }
+
+fn trace_transfer(arrow: &str, direction: &str, buffer: &[u8]) {
+    let hex = buffer
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    eprintln!("{arrow} {direction} [{}] [{hex}]", buffer.len());
+}
+
+pub struct TracingSerialUsb<T> {
+    inner: T,
+    trace: bool,
+}
+
+impl<T> TracingSerialUsb<T> {
+    pub fn new(inner: T, trace: bool) -> Self {
+        Self { inner, trace }
+    }
+}
+
+impl<T: SerialUsb> SerialUsb for TracingSerialUsb<T> {
+    fn init_serial_usb(&self, baud: BaudRate, parity: Parity, stop_bits: StopBits) -> Result<()> {
+        self.inner.init_serial_usb(baud, parity, stop_bits)
+    }
+
+    fn read_serial_usb(&self, buffer: &mut [u8; PACKET_SIZE as usize]) -> Result<()> {
+        self.inner.read_serial_usb(buffer)?;
+        if self.trace {
+            trace_transfer("<---", "READ", buffer);
+        }
+        Ok(())
+    }
+
+    fn write_serial_usb(&self, buffer: &[u8]) -> Result<()> {
+        if self.trace {
+            trace_transfer("--->", "WRITE", buffer);
+        }
+        self.inner.write_serial_usb(buffer)
+    }
+}
+
+const WRITE_CHUNK_SIZE: usize = PACKET_SIZE as usize - 1;
+
+pub struct UsbSerialStream<T: SerialUsb> {
+    inner: T,
+    read_buffer: VecDeque<u8>,
+    write_buffer: VecDeque<u8>,
+}
+
+impl<T: SerialUsb> UsbSerialStream<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            read_buffer: VecDeque::new(),
+            write_buffer: VecDeque::new(),
+        }
+    }
+
+    fn fill_read_buffer(&mut self) -> io::Result<()> {
+        let mut packet = [0; PACKET_SIZE as usize];
+        self.inner
+            .read_serial_usb(&mut packet)
+            .map_err(io::Error::other)?;
+        self.read_buffer.extend(bits_from_buffer(&packet));
+        Ok(())
+    }
+
+    fn send_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.inner
+            .write_serial_usb(chunk)
+            .map_err(io::Error::other)
+    }
+}
+
+impl<T: SerialUsb> Read for UsbSerialStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // The link is polled: an empty packet just means "nothing new yet",
+        // not end-of-stream, so keep polling until there is at least one
+        // byte to hand back (Read::read must not return Ok(0) for that).
+        while self.read_buffer.is_empty() {
+            self.fill_read_buffer()?;
+        }
+        let nb_bytes = self.read_buffer.len().min(buf.len());
+        for byte in buf.iter_mut().take(nb_bytes) {
+            *byte = self.read_buffer.pop_front().expect("checked above");
+        }
+        Ok(nb_bytes)
+    }
+}
+
+impl<T: SerialUsb> Write for UsbSerialStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buffer.extend(buf);
+        while self.write_buffer.len() >= WRITE_CHUNK_SIZE {
+            let chunk: Vec<u8> = self.write_buffer.drain(..WRITE_CHUNK_SIZE).collect();
+            self.send_chunk(&chunk)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.write_buffer.is_empty() {
+            let chunk: Vec<u8> = self.write_buffer.drain(..).collect();
+            self.send_chunk(&chunk)?;
+        }
+        Ok(())
+    }
+}
+
+// Moves USB polling off the display thread: the device is read in a loop on
+// a dedicated thread and each packet (or error) is handed over the channel,
+// so a slow terminal never stalls the link.
+pub fn spawn_reader<T>(
+    handle: T,
+    stop: Arc<AtomicBool>,
+) -> (
+    JoinHandle<()>,
+    mpsc::Receiver<Result<[u8; PACKET_SIZE as usize]>>,
+)
+where
+    T: SerialUsb + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let join_handle = thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+            let mut buffer = [0; PACKET_SIZE as usize];
+            match handle.read_serial_usb(&mut buffer) {
+                Ok(()) => {
+                    if sender.send(Ok(buffer)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    let _ = sender.send(Err(err));
+                    break;
+                }
+            }
+        }
+    });
+    (join_handle, receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baud_rate_mode_maps_known_rates() {
+        assert_eq!(baud_rate_mode(BaudRate::B2400), 0x13);
+        assert_eq!(baud_rate_mode(BaudRate::B4800), 0x23);
+        assert_eq!(baud_rate_mode(BaudRate::B9600), 0x33);
+        assert_eq!(baud_rate_mode(BaudRate::B19200), 0x43);
+    }
+
+    #[test]
+    fn parity_mode_maps_known_parities() {
+        assert_eq!(parity_mode(Parity::None), USBASP_MODE_PARITYN);
+        assert_eq!(parity_mode(Parity::Even), USBASP_MODE_PARITYE);
+        assert_eq!(parity_mode(Parity::Odd), USBASP_MODE_PARITYO);
+    }
+
+    #[test]
+    fn stop_bits_mode_maps_known_values() {
+        assert_eq!(stop_bits_mode(StopBits::One), USBASP_MODE_STOPBIT1);
+        assert_eq!(stop_bits_mode(StopBits::Two), USBASP_MODE_STOPBIT2);
+    }
+
+    #[test]
+    fn decode_text_line_accepts_valid_utf8() {
+        assert_eq!(decode_text_line(b"hello\n".to_vec()).unwrap(), "hello\n");
+    }
+
+    #[test]
+    fn decode_text_line_rejects_invalid_utf8() {
+        assert!(decode_text_line(vec![0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn hexdump_ascii_gutter_replaces_non_printables() {
+        let row = [b'h', b'i', 0x00, 0x1f, b' ', 0x7f];
+        assert_eq!(hexdump_ascii_gutter(&row), "hi.. .");
+    }
+
+    #[test]
+    fn format_hexdump_row_pads_short_final_row() {
+        let row = [0x41, 0x42, 0x43];
+        let line = format_hexdump_row(0, &row);
+        assert!(line.starts_with("00000000  41 42 43"));
+        assert!(line.ends_with("|ABC|"));
+    }
+
+    #[test]
+    fn format_hexdump_row_splits_groups_of_eight() {
+        let row = [0; 16];
+        let line = format_hexdump_row(0x10, &row);
+        assert_eq!(
+            line,
+            "00000010  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|"
+        );
+    }
+}